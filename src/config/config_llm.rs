@@ -3,6 +3,9 @@ use std::path::PathBuf;
 pub struct Config {
     pub model_path: PathBuf,
     pub model_arch: &'static str,
+    /// Maximum number of characters of prior conversation turns to prepend to a new
+    /// prompt when rebuilding per-session context.
+    pub context_window: usize,
 }
 
 impl Config {
@@ -10,6 +13,7 @@ impl Config {
         Self {
             model_path: PathBuf::from("open_llama_3b-f16.bin"),
             model_arch: "llama",
+            context_window: 2000,
         }
     }
 }
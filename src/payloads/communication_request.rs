@@ -1,9 +1,27 @@
 use serde::Deserialize;
 
+#[allow(dead_code)]
+#[derive(Debug, Default, Deserialize)]
+pub struct InferenceParamsInput {
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum CommunicationRequest {
     #[serde(rename = "ai_request")]
-    AIRequest { prompt: String },
+    AIRequest {
+        prompt: String,
+        #[serde(default)]
+        params: Option<InferenceParamsInput>,
+    },
+    #[serde(rename = "cancel_ai_request")]
+    CancelAIRequest { request_id: String },
 }
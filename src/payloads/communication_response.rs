@@ -9,6 +9,13 @@ pub enum CommunicationResponse {
         status: String,
         response: String,
     },
+    #[serde(rename = "ai_response_chunk")]
+    AIResponseChunk {
+        status: String,
+        request_id: String,
+        delta: String,
+        done: bool,
+    },
     #[serde(rename = "error")]
     Error {
         status: String,
@@ -0,0 +1,16 @@
+use serde::Deserialize;
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum ConnectionRequest {
+    #[serde(rename = "start_connection")]
+    StartConnection { user_id: u64 },
+    #[serde(rename = "disconnect")]
+    Disconnect { session_id: String, user_id: u64 },
+    #[serde(rename = "resume")]
+    Resume {
+        session_id: String,
+        resume_token: String,
+    },
+}
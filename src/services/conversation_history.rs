@@ -0,0 +1,73 @@
+use redis::{AsyncCommands, Client};
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+
+// Same expiry window used for session data in `cache_user_data`, so a conversation's
+// history never outlives the session it belongs to.
+const HISTORY_TTL_SECS: usize = 3600;
+
+// Caps how many turns are retained per session. Without a bound, a long-running
+// session's list grows forever and `build_context`'s LRANGE (which always fetches the
+// whole list before truncating to `max_chars`) gets slower every turn even though the
+// assembled context itself stays bounded.
+const MAX_HISTORY_TURNS: isize = 50;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Turn {
+    pub prompt: String,
+    pub response: String,
+}
+
+fn history_key(session_id: &str) -> String {
+    format!("session:history:{session_id}")
+}
+
+/// Append a completed prompt/response pair to the session's history, refreshing its TTL
+/// and trimming it down to the most recent `MAX_HISTORY_TURNS` so the list can't grow
+/// without bound over a long-running session.
+pub async fn append_turn(
+    redis: &Client,
+    session_id: &str,
+    turn: &Turn,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let key = history_key(session_id);
+    let payload = serde_json::to_string(turn)?;
+
+    redis::pipe()
+        .atomic()
+        .rpush(&key, payload)
+        .ltrim(&key, -MAX_HISTORY_TURNS, -1)
+        .expire(&key, HISTORY_TTL_SECS)
+        .query_async::<_, ()>(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Rebuild a combined context string from the most recent turns, newest-first until
+/// `max_chars` is reached, so the assembled prompt stays within the model's window.
+pub async fn build_context(
+    redis: &Client,
+    session_id: &str,
+    max_chars: usize,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let key = history_key(session_id);
+    let raw: Vec<String> = conn.lrange(&key, 0, -1).await?;
+
+    let mut context = String::new();
+    for entry in raw.iter().rev() {
+        let Ok(turn) = serde_json::from_str::<Turn>(entry) else {
+            continue;
+        };
+
+        let addition = format!("User: {}\nAssistant: {}\n", turn.prompt, turn.response);
+        if context.len() + addition.len() > max_chars {
+            break;
+        }
+        context.insert_str(0, &addition);
+    }
+
+    Ok(context)
+}
@@ -1,11 +1,100 @@
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{convert::Infallible, error::Error, path::PathBuf, sync::Arc};
 use llm::{InferenceParameters, InferenceRequest, InferenceResponse, InferenceFeedback, Model, ModelArchitecture, TokenizerSource};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc::UnboundedSender, watch, Mutex};
 use crate::config::config_llm::Config;
 
+// Caps how many inferences the single model mutex is asked to serve at once; requests
+// beyond this are turned away by `try_begin_inference` instead of piling up inside the
+// mutex itself.
+const MAX_CONCURRENT_INFERENCES: u64 = 4;
+
+// Hard ceiling on client-requested generation length, so a malicious or buggy client
+// can't tie up the model mutex with an unbounded token count.
+const MAX_ALLOWED_TOKENS: usize = 512;
+const DEFAULT_MAX_TOKENS: usize = 140;
+
+/// Generation parameters accepted from a client request, clamped to safe ranges before
+/// being handed to the model.
+#[derive(Debug, Clone)]
+pub struct InferenceParams {
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    pub max_tokens: usize,
+    pub stop: Vec<String>,
+}
+
+impl Default for InferenceParams {
+    fn default() -> Self {
+        let defaults = InferenceParameters::default();
+        Self {
+            temperature: defaults.temperature,
+            top_k: defaults.top_k,
+            top_p: defaults.top_p,
+            repeat_penalty: defaults.repeat_penalty,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            stop: Vec::new(),
+        }
+    }
+}
+
+impl InferenceParams {
+    /// Builds params from client-supplied, optional overrides, clamping each one to a
+    /// safe range so a client can't request pathological generation behavior.
+    pub fn from_overrides(
+        temperature: Option<f32>,
+        top_k: Option<usize>,
+        top_p: Option<f32>,
+        repeat_penalty: Option<f32>,
+        max_tokens: Option<usize>,
+        stop: Vec<String>,
+    ) -> Self {
+        let defaults = Self::default();
+        Self {
+            temperature: temperature.unwrap_or(defaults.temperature).clamp(0.0, 2.0),
+            top_k: top_k.unwrap_or(defaults.top_k).clamp(1, 100),
+            top_p: top_p.unwrap_or(defaults.top_p).clamp(0.0, 1.0),
+            repeat_penalty: repeat_penalty.unwrap_or(defaults.repeat_penalty).clamp(0.5, 2.0),
+            max_tokens: max_tokens.unwrap_or(defaults.max_tokens).clamp(1, MAX_ALLOWED_TOKENS),
+            stop,
+        }
+    }
+
+    fn to_llm_parameters(&self) -> InferenceParameters {
+        InferenceParameters {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            repeat_penalty: self.repeat_penalty,
+            ..InferenceParameters::default()
+        }
+    }
+
+    /// True once `suffix` contains one of the configured stop sequences.
+    fn hit_stop_sequence(&self, suffix: &str) -> bool {
+        self.stop.iter().any(|s| !s.is_empty() && suffix.contains(s.as_str()))
+    }
+}
+
+/// Held for the lifetime of one inference; releases its reserved slot on drop so every
+/// exit path (success, error, early return) frees it exactly once.
+pub struct InferenceGuard {
+    in_flight: Arc<AtomicU64>,
+}
+
+impl Drop for InferenceGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 #[derive(Clone)]
 pub struct LlmService {
     model: Arc<Mutex<Box<dyn Model>>>,
+    context_window: usize,
+    in_flight: Arc<AtomicU64>,
 }
 
 impl LlmService {
@@ -32,27 +121,71 @@ impl LlmService {
         println!("Model loaded successfully.");
         Ok(Self {
             model: Arc::new(Mutex::new(model)),
+            context_window: cfg.context_window,
+            in_flight: Arc::new(AtomicU64::new(0)),
         })
     }
 
-    pub async fn run_prompt(&self, prompt: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    /// Cap, in characters, that callers should use when assembling prior conversation
+    /// turns into a combined prompt for this model.
+    pub fn context_window(&self) -> usize {
+        self.context_window
+    }
+
+    /// Reserves a concurrent inference slot, returning `None` once
+    /// `MAX_CONCURRENT_INFERENCES` are already in flight.
+    pub fn try_begin_inference(&self) -> Option<InferenceGuard> {
+        let mut current = self.in_flight.load(Ordering::SeqCst);
+        loop {
+            if current >= MAX_CONCURRENT_INFERENCES {
+                return None;
+            }
+            match self.in_flight.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(InferenceGuard { in_flight: self.in_flight.clone() }),
+                Err(observed) => current = observed,
+            }
+        }
+    }
+
+    /// Builds the combined prompt sent to the model: prior turns (if any) followed by
+    /// the new user prompt.
+    fn assemble_prompt(history: &str, prompt: &str) -> String {
+        format!("{history}User: {prompt}\nAssistant:")
+    }
+
+    pub async fn run_prompt(
+        &self,
+        prompt: &str,
+        history: &str,
+        params: &InferenceParams,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
         let model = self.model.lock().await;
         let mut session = model.start_session(Default::default());
         let mut output = String::new();
+        let combined_prompt = Self::assemble_prompt(history, prompt);
+        let llm_parameters = params.to_llm_parameters();
 
         session.infer::<Infallible>(
             model.as_ref(),
             &mut rand::thread_rng(),
             &InferenceRequest {
-                prompt: prompt.into(),
-                parameters: &InferenceParameters::default(),
+                prompt: combined_prompt.as_str().into(),
+                parameters: &llm_parameters,
                 play_back_previous_tokens: false,
-                maximum_token_count: Some(140),
+                maximum_token_count: Some(params.max_tokens),
             },
             &mut Default::default(),
             |resp| {
                 if let InferenceResponse::InferredToken(t) = resp {
                     output.push_str(&t);
+                    if params.hit_stop_sequence(&output) {
+                        return Ok(InferenceFeedback::Halt);
+                    }
                 }
                 Ok(InferenceFeedback::Continue)
             },
@@ -61,5 +194,65 @@ impl LlmService {
         Ok(output)
     }
 
+    /// Like `run_prompt`, but forwards each inferred token to `sender` as soon as it is
+    /// produced instead of buffering the whole reply. The inference itself runs on its
+    /// own task so the caller only ever touches the receiving end of the channel, never
+    /// the `model` mutex directly. `sender` carries `Err` exactly once, as its last
+    /// message, if inference fails partway through — the caller can then tell a model
+    /// error apart from a clean finish instead of seeing the channel simply close.
+    pub async fn run_prompt_streaming(
+        &self,
+        prompt: &str,
+        history: &str,
+        params: InferenceParams,
+        sender: UnboundedSender<Result<String, String>>,
+        mut cancel: watch::Receiver<bool>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let model = self.model.clone();
+        let combined_prompt = Self::assemble_prompt(history, prompt);
+
+        tokio::spawn(async move {
+            let model = model.lock().await;
+            let mut session = model.start_session(Default::default());
+            let llm_parameters = params.to_llm_parameters();
+            let mut generated = String::new();
 
+            let result = session.infer::<Infallible>(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &InferenceRequest {
+                    prompt: combined_prompt.as_str().into(),
+                    parameters: &llm_parameters,
+                    play_back_previous_tokens: false,
+                    maximum_token_count: Some(params.max_tokens),
+                },
+                &mut Default::default(),
+                |resp| {
+                    // Checked on every token so a cancellation request lands as soon as
+                    // the current token finishes, not just between whole requests.
+                    if *cancel.borrow() {
+                        return Ok(InferenceFeedback::Halt);
+                    }
+                    if let InferenceResponse::InferredToken(t) = resp {
+                        generated.push_str(&t);
+                        // The receiver may have been dropped if the client disconnected
+                        // mid-generation; stop feeding tokens into a channel no one reads.
+                        if sender.send(Ok(t)).is_err() {
+                            return Ok(InferenceFeedback::Halt);
+                        }
+                        if params.hit_stop_sequence(&generated) {
+                            return Ok(InferenceFeedback::Halt);
+                        }
+                    }
+                    Ok(InferenceFeedback::Continue)
+                },
+            );
+
+            if let Err(e) = result {
+                let _ = sender.send(Err(e.to_string()));
+            }
+        });
+
+        Ok(())
+    }
 }
@@ -0,0 +1,42 @@
+use redis::{AsyncCommands, Client};
+use redis::aio::Connection;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const WINDOW_SECS: u64 = 60;
+const MAX_REQUESTS_PER_WINDOW: u64 = 20;
+
+pub struct RateLimitExceeded {
+    pub retry_after_secs: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Fixed-window request counter keyed by `user_id` and the current minute bucket, so
+/// stale counters expire on their own rather than needing an explicit sweep.
+pub async fn check_and_increment(
+    redis: &Client,
+    user_id: u64,
+) -> Result<Result<(), RateLimitExceeded>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let now = now_secs();
+    let window = now / WINDOW_SECS;
+    let key = format!("ratelimit:{user_id}:{window}");
+
+    let count: u64 = conn.incr(&key, 1).await?;
+    if count == 1 {
+        let _: () = conn.expire(&key, WINDOW_SECS as usize).await?;
+    }
+
+    if count > MAX_REQUESTS_PER_WINDOW {
+        return Ok(Err(RateLimitExceeded {
+            retry_after_secs: WINDOW_SECS - (now % WINDOW_SECS),
+        }));
+    }
+
+    Ok(Ok(()))
+}
@@ -0,0 +1,84 @@
+use redis::{AsyncCommands, Client};
+use redis::aio::Connection;
+use uuid::Uuid;
+
+// How long a detached session is kept around (and its resume token considered valid)
+// before `remove_session_data` is allowed to purge it for good.
+pub const GRACE_PERIOD_SECS: u64 = 120;
+
+fn resume_token_key(session_id: &str) -> String {
+    format!("session:resume:{session_id}")
+}
+
+fn detached_key(session_id: &str) -> String {
+    format!("session:detached:{session_id}")
+}
+
+/// Issues a fresh single-use resume token for `session_id`. The token is stored with no
+/// expiry of its own — it stays valid for as long as the session is connected, and only
+/// starts counting down once the session actually detaches (see `mark_detached`), so the
+/// grace window always starts at disconnect time, never at connect time.
+pub async fn issue_resume_token(
+    redis: &Client,
+    session_id: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let token = Uuid::new_v4().to_string();
+
+    conn.set(resume_token_key(session_id), &token).await?;
+
+    Ok(token)
+}
+
+/// Marks a session as detached instead of purging it outright, giving the client a
+/// window to reconnect and resume before `remove_session_data` runs. Also puts the
+/// outstanding resume token on the same countdown, since it was stored without its own
+/// expiry at issuance time.
+pub async fn mark_detached(
+    redis: &Client,
+    session_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    redis::pipe()
+        .atomic()
+        .set_ex(detached_key(session_id), "1", GRACE_PERIOD_SECS)
+        .expire(resume_token_key(session_id), GRACE_PERIOD_SECS as i64)
+        .query_async::<_, ()>(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Cancels a pending purge because the client resumed before the grace period elapsed.
+pub async fn cancel_detach(
+    redis: &Client,
+    session_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let _: () = conn.del(detached_key(session_id)).await?;
+    Ok(())
+}
+
+/// True if `session_id` was marked detached and the grace window hasn't elapsed yet.
+pub async fn is_detached(
+    redis: &Client,
+    session_id: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let exists: bool = conn.exists(detached_key(session_id)).await?;
+    Ok(exists)
+}
+
+/// Validates and consumes a resume token. Single-use: the token is fetched and deleted
+/// in one atomic `GETDEL`, so two concurrent resume attempts racing the same token can
+/// never both observe it as present — whichever loses the race sees it already gone.
+pub async fn validate_and_consume(
+    redis: &Client,
+    session_id: &str,
+    presented_token: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let key = resume_token_key(session_id);
+    let stored: Option<String> = redis::cmd("GETDEL").arg(&key).query_async(&mut conn).await?;
+
+    Ok(matches!(stored, Some(t) if t == presented_token))
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::StreamExt;
+use redis::aio::Connection;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+
+// Each node subscribes to its own channel so a fan-out message is published directly to
+// the node that owns the target client instead of being flooded to the whole cluster.
+fn node_channel(node_id: &str) -> String {
+    format!("ws:fanout:{node_id}")
+}
+
+// TTL of the shared `client:node` hash. It applies to the hash as a whole and is renewed
+// whenever any client is claimed; individual claims carry no expiry of their own.
+const CLIENT_CLAIM_TTL_SECS: usize = 3600;
+// How often a still-connected client's claim is re-asserted, well inside
+// `CLIENT_CLAIM_TTL_SECS`, so a long-lived connection never falls out of the routing
+// table while it's still connected.
+const CLIENT_CLAIM_REFRESH_SECS: u64 = 300;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FanoutEnvelope {
+    client_id: Uuid,
+    message: String,
+}
+
+pub struct WsBroadcaster {
+    clients: Mutex<HashMap<Uuid, mpsc::UnboundedSender<String>>>,
+    redis: Arc<Client>,
+    node_id: String,
+}
+
+impl WsBroadcaster {
+    /// Constructs a broadcaster and immediately starts its fan-out subscriber loop in
+    /// the background, so the instance is listening for cross-node deliveries from the
+    /// moment it exists — a node that never subscribes would otherwise silently drop
+    /// every `publish_remote` send aimed at it.
+    pub fn new(redis: Arc<Client>) -> Arc<Self> {
+        let broadcaster = Arc::new(Self {
+            clients: Mutex::new(HashMap::new()),
+            redis,
+            node_id: Uuid::new_v4().to_string(),
+        });
+
+        tokio::spawn({
+            let broadcaster = broadcaster.clone();
+            async move {
+                if let Err(e) = broadcaster.run_fanout_subscriber().await {
+                    eprintln!("Fan-out subscriber exited: {e}");
+                }
+            }
+        });
+
+        broadcaster
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub async fn add_client(&self, client_id: Uuid, sender: mpsc::UnboundedSender<String>) {
+        self.clients.lock().await.insert(client_id, sender);
+        let _ = self.claim_client(&client_id).await;
+    }
+
+    pub async fn remove_client(&self, client_id: &Uuid) {
+        self.clients.lock().await.remove(client_id);
+        let _ = self.release_client(client_id).await;
+    }
+
+    /// Keeps re-asserting `client_id`'s `client:node` claim on this node every
+    /// `CLIENT_CLAIM_REFRESH_SECS` for as long as it stays connected here, so a
+    /// connection that outlives the claim's TTL doesn't fall out of the cluster's
+    /// routing table while it's still open. Stops on its own once the client
+    /// disconnects.
+    pub fn spawn_claim_refresh(self: Arc<Self>, client_id: Uuid) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(CLIENT_CLAIM_REFRESH_SECS)).await;
+                if !self.clients.lock().await.contains_key(&client_id) {
+                    break;
+                }
+                let _ = self.claim_client(&client_id).await;
+            }
+        });
+    }
+
+    /// Delivers `message` to `client_id`. If the client isn't connected to this node,
+    /// the message is published to the Redis channel of whichever node last claimed it,
+    /// so a deployment running several instances behind a load balancer can still reach
+    /// a client pinned elsewhere.
+    pub async fn send_to(
+        &self,
+        client_id: &Uuid,
+        message: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(sender) = self.clients.lock().await.get(client_id) {
+            return sender.send(message).map_err(|e| e.to_string().into());
+        }
+
+        self.publish_remote(client_id, message).await
+    }
+
+    // Records which node a client is attached to, so other nodes can route messages to
+    // it directly rather than flooding every node in the cluster. Routing is keyed by
+    // `client_id` rather than session, since `send_to` only ever has a `client_id` to
+    // route on and a session's `session:user`/`session:data` hashes carry no connection
+    // identity of their own.
+    async fn claim_client(&self, client_id: &Uuid) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn: Connection = self.redis.get_async_connection().await?;
+        redis::pipe()
+            .atomic()
+            .hset("client:node", client_id.to_string(), &self.node_id)
+            .expire("client:node", CLIENT_CLAIM_TTL_SECS)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    // Drops the claim outright on disconnect instead of waiting for its TTL, so another
+    // node can't transiently see a stale owner for a client that's already gone.
+    async fn release_client(&self, client_id: &Uuid) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn: Connection = self.redis.get_async_connection().await?;
+        let _: () = conn.hdel("client:node", client_id.to_string()).await?;
+        Ok(())
+    }
+
+    async fn publish_remote(
+        &self,
+        client_id: &Uuid,
+        message: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn: Connection = self.redis.get_async_connection().await?;
+        let owning_node: Option<String> = conn.hget("client:node", client_id.to_string()).await?;
+        let Some(owning_node) = owning_node else {
+            return Err(format!("No known node for client {client_id}").into());
+        };
+
+        let envelope = FanoutEnvelope { client_id: *client_id, message };
+        let _: () = conn
+            .publish(node_channel(&owning_node), serde_json::to_string(&envelope)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Subscribes to this node's fan-out channel and delivers anything published there
+    /// to whichever of its own clients the message is addressed to. Runs for the
+    /// lifetime of the process; `new` spawns it automatically so callers never need to
+    /// remember to start it separately.
+    pub async fn run_fanout_subscriber(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = self.redis.get_async_connection().await?;
+        let mut pubsub = conn.into_pubsub();
+        pubsub.subscribe(node_channel(&self.node_id)).await?;
+        let mut messages = pubsub.on_message();
+
+        while let Some(msg) = messages.next().await {
+            let Ok(payload) = msg.get_payload::<String>() else {
+                continue;
+            };
+            let Ok(envelope) = serde_json::from_str::<FanoutEnvelope>(&payload) else {
+                continue;
+            };
+
+            if let Some(sender) = self.clients.lock().await.get(&envelope.client_id) {
+                let _ = sender.send(envelope.message);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,17 +1,26 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use redis::{AsyncCommands, Client};
 use redis::aio::Connection;
 use serde_json::{json, Value};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self};
+use tokio::sync::{watch, Mutex as AsyncMutex};
 use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
 use crate::payloads::communication_request::CommunicationRequest;
 use crate::payloads::communication_response::CommunicationResponse;
 use crate::payloads::connection_request::ConnectionRequest;
-use crate::services::llm_service::LlmService;
+use crate::services::conversation_history;
+use crate::services::llm_service::{InferenceParams, LlmService};
+use crate::services::rate_limiter;
+use crate::services::resume;
 use crate::{
     services::user_service::UserService,
     utils::jwt::Claims,
@@ -49,7 +58,7 @@ pub async fn remove_session_data(
     session_id: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut conn: Connection = redis.get_async_connection().await?;
-    
+
     redis::pipe()
         .atomic()
         .hdel("session:data", session_id)
@@ -60,6 +69,18 @@ pub async fn remove_session_data(
     Ok(())
 }
 
+// Look up the user_id cached for a still-detached session, used to re-authenticate a
+// resumed connection without asking the client to present its JWT again.
+async fn load_cached_user_id(
+    redis: &Client,
+    session_id: &str,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn: Connection = redis.get_async_connection().await?;
+    let raw: Option<String> = conn.hget("session:user", session_id).await?;
+    let raw = raw.ok_or("Session not found")?;
+    Ok(raw.parse()?)
+}
+
 pub async fn handle_ws_connection(
     ws_stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
     client_id: Uuid,
@@ -71,27 +92,8 @@ pub async fn handle_ws_connection(
 ) {
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    // 1. AUTHENTICATION PHASE 
-    let (user_id, claims) = match ws_receiver.next().await {
-        Some(Ok(first_msg)) => {
-            match WsAuth::from_first_message(&first_msg).await {
-                Ok(WsAuth(claims)) => {
-                    println!("[{client_id}] JWT authentication succeeded");
-                    (claims.sub as u64, claims)
-                }
-                Err((code, msg)) => {
-                    let _ = ws_sender.send(Message::Text(
-                            json!({
-                                "type": "error",
-                                "status": "authentication_failed",
-                                "error": msg,
-                                "code": code.as_u16()
-                            }).to_string().into() 
-                        )).await;
-                    return;
-                }
-            }
-        }
+    let first_msg = match ws_receiver.next().await {
+        Some(Ok(msg)) => msg,
         Some(Err(e)) => {
             let _ = ws_sender.send(Message::Text(
                 json!({
@@ -116,9 +118,105 @@ pub async fn handle_ws_connection(
         }
     };
 
+    // A reconnecting client presents a resume token instead of a fresh JWT.
+    if let Message::Text(text) = &first_msg {
+        if let Ok(ConnectionRequest::Resume { session_id, resume_token }) = serde_json::from_str::<ConnectionRequest>(text) {
+            let valid = resume::validate_and_consume(&redis_client, &session_id, &resume_token)
+                .await
+                .unwrap_or(false)
+                && resume::is_detached(&redis_client, &session_id).await.unwrap_or(false);
+
+            if !valid {
+                let _ = ws_sender.send(Message::Text(
+                    json!({
+                        "type": "error",
+                        "status": "resume_failed",
+                        "error": "Resume token invalid or expired",
+                        "code": 401
+                    }).to_string().into()
+                )).await;
+                return;
+            }
+
+            let _ = resume::cancel_detach(&redis_client, &session_id).await;
+
+            let user_id = match load_cached_user_id(&redis_client, &session_id).await {
+                Ok(id) => id,
+                Err(e) => {
+                    let _ = ws_sender.send(Message::Text(
+                        json!({
+                            "type": "error",
+                            "status": "resume_failed",
+                            "error": format!("Failed to load session: {}", e),
+                            "code": 500
+                        }).to_string().into()
+                    )).await;
+                    return;
+                }
+            };
+
+            // validate_and_consume deletes the presented token unconditionally, so the
+            // session needs a new one to present the next time it drops.
+            let resume_token = match resume::issue_resume_token(&redis_client, &session_id).await {
+                Ok(token) => token,
+                Err(e) => {
+                    let _ = ws_sender.send(Message::Text(
+                        json!({
+                            "type": "error",
+                            "status": "resume_failed",
+                            "error": format!("Failed to issue resume token: {}", e),
+                            "code": 500
+                        }).to_string().into()
+                    )).await;
+                    return;
+                }
+            };
+
+            println!("[{client_id}] Resumed session {session_id}");
+
+            let (tx, rx) = mpsc::unbounded_channel();
+            broadcaster.add_client(client_id, tx).await;
+            broadcaster.clone().spawn_claim_refresh(client_id);
+
+            let _ = ws_sender.send(Message::Text(
+                serde_json::to_string(&CommunicationResponse::AIResponse {
+                    status: "session_resumed".to_string(),
+                    response: json!({
+                        "session_id": session_id,
+                        "user_id": user_id,
+                        "resume_token": resume_token
+                    }).to_string(),
+                }).unwrap().into()
+            )).await;
+
+            run_session(ws_sender, ws_receiver, rx, client_id, session_id, user_id, broadcaster, redis_client, llm_service).await;
+            println!("[{}] Connection closed", client_id);
+            return;
+        }
+    }
+
+    // 1. AUTHENTICATION PHASE
+    let (user_id, claims) = match WsAuth::from_first_message(&first_msg).await {
+        Ok(WsAuth(claims)) => {
+            println!("[{client_id}] JWT authentication succeeded");
+            (claims.sub as u64, claims)
+        }
+        Err((code, msg)) => {
+            let _ = ws_sender.send(Message::Text(
+                    json!({
+                        "type": "error",
+                        "status": "authentication_failed",
+                        "error": msg,
+                        "code": code.as_u16()
+                    }).to_string().into()
+                )).await;
+            return;
+        }
+    };
+
     // 2. SESSION CREATION
     let session_id = Uuid::new_v4().to_string();
-    
+
     // Cache user data in Redis
     if let Err(e) = cache_user_data(&redis_client, &session_id, user_id, &claims).await {
         let _ = ws_sender.send(Message::Text(
@@ -132,9 +230,26 @@ pub async fn handle_ws_connection(
         return;
     }
 
+    // Issue a resume token so a dropped connection can be reattached within the grace window
+    let resume_token = match resume::issue_resume_token(&redis_client, &session_id).await {
+        Ok(token) => token,
+        Err(e) => {
+            let _ = ws_sender.send(Message::Text(
+                json!({
+                    "type": "error",
+                    "status": "cache_error",
+                    "error": format!("Failed to issue resume token: {}", e),
+                    "code": 500
+                }).to_string().into()
+            )).await;
+            return;
+        }
+    };
+
     // Register client
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, rx) = mpsc::unbounded_channel();
     broadcaster.add_client(client_id, tx).await;
+    broadcaster.clone().spawn_claim_refresh(client_id);
 
     // Send session info
     let _ = ws_sender.send(Message::Text(
@@ -142,16 +257,39 @@ pub async fn handle_ws_connection(
             status: "session_created".to_string(),
             response: json!({
                 "session_id": session_id,
-                "user_id": user_id
+                "user_id": user_id,
+                "resume_token": resume_token
             }).to_string(),
         }).unwrap().into()
     )).await;
 
-    // 3. MAIN MESSAGE PROCESSING LOOP
-   let process_task = tokio::spawn({
+    run_session(ws_sender, ws_receiver, rx, client_id, session_id, user_id, broadcaster, redis_client, llm_service).await;
+
+    println!("[{}] Connection closed", client_id);
+}
+
+// 3. MAIN MESSAGE PROCESSING LOOP, shared by fresh and resumed connections.
+async fn run_session(
+    mut ws_sender: SplitSink<WebSocketStream<TcpStream>, Message>,
+    mut ws_receiver: SplitStream<WebSocketStream<TcpStream>>,
+    mut rx: mpsc::UnboundedReceiver<String>,
+    client_id: Uuid,
+    session_id: String,
+    user_id: u64,
+    broadcaster: Arc<WsBroadcaster>,
+    redis_client: Arc<Client>,
+    llm_service: Arc<LlmService>,
+) {
+    // Tracks in-flight AIRequests for this connection so a CancelAIRequest can signal
+    // the matching inference to stop.
+    let cancel_registry: Arc<AsyncMutex<HashMap<String, watch::Sender<bool>>>> =
+        Arc::new(AsyncMutex::new(HashMap::new()));
+
+    let process_task = tokio::spawn({
         let broadcaster = broadcaster.clone();
         let redis_client = redis_client.clone();
         let session_id = session_id.clone();
+        let cancel_registry = cancel_registry.clone();
         async move {
             while let Some(Ok(msg)) = ws_receiver.next().await {
                 match msg {
@@ -162,7 +300,7 @@ pub async fn handle_ws_connection(
                                 match conn_req {
                                     ConnectionRequest::Disconnect { session_id: req_session_id, user_id: _ } => {
                                         if req_session_id == session_id {
-                                            // Clean up and disconnect
+                                            // Explicit disconnect: purge immediately, no resume grace period.
                                             let _ = remove_session_data(&redis_client, &session_id).await;
                                             let _ = broadcaster.send_to(
                                                 &client_id,
@@ -174,7 +312,7 @@ pub async fn handle_ws_connection(
                                             break;
                                         }
                                     }
-                                    ConnectionRequest::StartConnection { .. } => {
+                                    ConnectionRequest::StartConnection { .. } | ConnectionRequest::Resume { .. } => {
                                         // Already authenticated, ignore new connection requests
                                         let _ = broadcaster.send_to(
                                             &client_id,
@@ -191,35 +329,209 @@ pub async fn handle_ws_connection(
                                 match serde_json::from_str::<CommunicationRequest>(&text) {
                                     Ok(comm_req) => {
                                         match comm_req {
-                                            CommunicationRequest::AIRequest { prompt } => {
+                                            CommunicationRequest::AIRequest { prompt, params } => {
+                                                let inference_params = match params {
+                                                    Some(p) => InferenceParams::from_overrides(
+                                                        p.temperature,
+                                                        p.top_k,
+                                                        p.top_p,
+                                                        p.repeat_penalty,
+                                                        p.max_tokens,
+                                                        p.stop,
+                                                    ),
+                                                    None => InferenceParams::default(),
+                                                };
                                                 let llm_service = llm_service.clone();
                                                 let broadcaster = broadcaster.clone();
                                                 let client_id = client_id.clone();
+                                                let redis_client = redis_client.clone();
+                                                let session_id = session_id.clone();
+                                                let cancel_registry = cancel_registry.clone();
+
+                                                // Server-generated so a client can cancel a specific in-flight
+                                                // request via CancelAIRequest without racing other requests.
+                                                let request_id = Uuid::new_v4().to_string();
+                                                let (cancel_tx, cancel_rx) = watch::channel(false);
+                                                cancel_registry.lock().await.insert(request_id.clone(), cancel_tx);
+
+                                                // Hands the client its request_id as soon as the request is
+                                                // queued, not just on first token, so it has something to put
+                                                // in a CancelAIRequest even while still behind rate-limit/
+                                                // capacity checks or waiting on the model mutex.
+                                                let _ = broadcaster.send_to(
+                                                    &client_id,
+                                                    serde_json::to_string(&CommunicationResponse::AIResponseChunk {
+                                                        status: "accepted".to_string(),
+                                                        request_id: request_id.clone(),
+                                                        delta: String::new(),
+                                                        done: false,
+                                                    }).unwrap()
+                                                ).await;
 
                                                 tokio::spawn(async move {
-                                                    match llm_service.run_prompt(&prompt).await {
-                                                        Ok(ai_output) => {
+                                                    match rate_limiter::check_and_increment(&redis_client, user_id).await {
+                                                        Ok(Ok(())) => {}
+                                                        Ok(Err(limit)) => {
                                                             let _ = broadcaster.send_to(
                                                                 &client_id,
-                                                                serde_json::to_string(&CommunicationResponse::AIResponse {
-                                                                    status: "success".to_string(),
-                                                                    response: ai_output,
+                                                                serde_json::to_string(&CommunicationResponse::Error {
+                                                                    status: "rate_limited".to_string(),
+                                                                    error: format!("Too many requests, retry after {}s", limit.retry_after_secs),
                                                                 }).unwrap()
                                                             ).await;
+                                                            cancel_registry.lock().await.remove(&request_id);
+                                                            return;
                                                         }
                                                         Err(e) => {
                                                             let _ = broadcaster.send_to(
                                                                 &client_id,
                                                                 serde_json::to_string(&CommunicationResponse::Error {
                                                                     status: "ai_error".to_string(),
-                                                                    error: format!("AI processing failed: {}", e),
+                                                                    error: format!("Rate limit check failed: {}", e),
                                                                 }).unwrap()
                                                             ).await;
+                                                            cancel_registry.lock().await.remove(&request_id);
+                                                            return;
                                                         }
                                                     }
+
+                                                    // Bounds how many inferences contend for the model mutex at once; held
+                                                    // for the whole request and released on every exit path via Drop.
+                                                    let Some(_inference_guard) = llm_service.try_begin_inference() else {
+                                                        let _ = broadcaster.send_to(
+                                                            &client_id,
+                                                            serde_json::to_string(&CommunicationResponse::Error {
+                                                                status: "rate_limited".to_string(),
+                                                                error: "Server is at capacity, retry after 1s".to_string(),
+                                                            }).unwrap()
+                                                        ).await;
+                                                        cancel_registry.lock().await.remove(&request_id);
+                                                        return;
+                                                    };
+
+                                                    let history = conversation_history::build_context(
+                                                        &redis_client,
+                                                        &session_id,
+                                                        llm_service.context_window(),
+                                                    ).await.unwrap_or_default();
+
+                                                    let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel();
+
+                                                    if let Err(e) = llm_service.run_prompt_streaming(&prompt, &history, inference_params, chunk_tx, cancel_rx.clone()).await {
+                                                        let _ = broadcaster.send_to(
+                                                            &client_id,
+                                                            serde_json::to_string(&CommunicationResponse::Error {
+                                                                status: "ai_error".to_string(),
+                                                                error: format!("AI processing failed: {}", e),
+                                                            }).unwrap()
+                                                        ).await;
+                                                        cancel_registry.lock().await.remove(&request_id);
+                                                        return;
+                                                    }
+
+                                                    // The model mutex is only held by the spawned inference task, so
+                                                    // draining the channel here never blocks behind it. Racing the
+                                                    // cancel signal alongside it lets a CancelAIRequest stop the
+                                                    // client-facing stream as soon as it fires, without waiting on
+                                                    // the inference task to notice on its own.
+                                                    let mut full_response = String::new();
+                                                    let mut inference_error: Option<String> = None;
+                                                    let mut cancel_watch = cancel_rx.clone();
+                                                    loop {
+                                                        tokio::select! {
+                                                            maybe_delta = chunk_rx.recv() => {
+                                                                match maybe_delta {
+                                                                    Some(Ok(delta)) => {
+                                                                        full_response.push_str(&delta);
+                                                                        let _ = broadcaster.send_to(
+                                                                            &client_id,
+                                                                            serde_json::to_string(&CommunicationResponse::AIResponseChunk {
+                                                                                status: "streaming".to_string(),
+                                                                                request_id: request_id.clone(),
+                                                                                delta,
+                                                                                done: false,
+                                                                            }).unwrap()
+                                                                        ).await;
+                                                                    }
+                                                                    Some(Err(e)) => {
+                                                                        inference_error = Some(e);
+                                                                        break;
+                                                                    }
+                                                                    None => break,
+                                                                }
+                                                            }
+                                                            _ = cancel_watch.changed() => {
+                                                                if *cancel_watch.borrow() {
+                                                                    break;
+                                                                }
+                                                            }
+                                                        }
+                                                    }
+
+                                                    cancel_registry.lock().await.remove(&request_id);
+
+                                                    if let Some(e) = inference_error {
+                                                        let _ = broadcaster.send_to(
+                                                            &client_id,
+                                                            serde_json::to_string(&CommunicationResponse::Error {
+                                                                status: "ai_error".to_string(),
+                                                                error: format!("AI processing failed: {}", e),
+                                                            }).unwrap()
+                                                        ).await;
+                                                        return;
+                                                    }
+
+                                                    if *cancel_rx.borrow() {
+                                                        let _ = broadcaster.send_to(
+                                                            &client_id,
+                                                            serde_json::to_string(&CommunicationResponse::AIResponseChunk {
+                                                                status: "cancelled".to_string(),
+                                                                request_id: request_id.clone(),
+                                                                delta: String::new(),
+                                                                done: true,
+                                                            }).unwrap()
+                                                        ).await;
+                                                        return;
+                                                    }
+
+                                                    let _ = broadcaster.send_to(
+                                                        &client_id,
+                                                        serde_json::to_string(&CommunicationResponse::AIResponseChunk {
+                                                            status: "streaming".to_string(),
+                                                            request_id: request_id.clone(),
+                                                            delta: String::new(),
+                                                            done: true,
+                                                        }).unwrap()
+                                                    ).await;
+
+                                                    let _ = conversation_history::append_turn(
+                                                        &redis_client,
+                                                        &session_id,
+                                                        &conversation_history::Turn {
+                                                            prompt,
+                                                            response: full_response,
+                                                        },
+                                                    ).await;
                                                 });
                                             }
 
+                                            CommunicationRequest::CancelAIRequest { request_id } => {
+                                                let cancel_tx = cancel_registry.lock().await.remove(&request_id);
+                                                match cancel_tx {
+                                                    Some(tx) => {
+                                                        let _ = tx.send(true);
+                                                    }
+                                                    None => {
+                                                        let _ = broadcaster.send_to(
+                                                            &client_id,
+                                                            serde_json::to_string(&CommunicationResponse::Error {
+                                                                status: "invalid_request".to_string(),
+                                                                error: "No in-flight request with that id".to_string(),
+                                                            }).unwrap()
+                                                        ).await;
+                                                    }
+                                                }
+                                            }
                                         }
                                     }
                                     Err(_) => {
@@ -250,9 +562,23 @@ pub async fn handle_ws_connection(
                 }
             }
 
-            // Clean up on disconnect
+            // Clean up on disconnect: don't purge outright, give the client a grace
+            // window to present a resume token and reattach to this same session.
             broadcaster.remove_client(&client_id).await;
-            let _ = remove_session_data(&redis_client, &session_id).await;
+            if resume::mark_detached(&redis_client, &session_id).await.is_ok() {
+                let redis_client = redis_client.clone();
+                let session_id = session_id.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs(resume::GRACE_PERIOD_SECS)).await;
+                    // Only purge if nobody resumed in the meantime (a successful
+                    // resume cancels the detached marker).
+                    if resume::is_detached(&redis_client, &session_id).await.unwrap_or(false) {
+                        let _ = remove_session_data(&redis_client, &session_id).await;
+                    }
+                });
+            } else {
+                let _ = remove_session_data(&redis_client, &session_id).await;
+            }
         }
     });
 
@@ -269,6 +595,4 @@ pub async fn handle_ws_connection(
         _ = process_task => (),
         _ = send_task => (),
     }
-
-    println!("[{}] Connection closed", client_id);
-}
\ No newline at end of file
+}